@@ -1,16 +1,28 @@
 use core::fmt::Debug;
 
-use crate::comms::{Opcode, Status};
+use crate::comms::{
+    command_with_addr, AddressMode, EraseSize, Opcode, ReadMode, Status, PAGE_SIZE, SECTOR_SIZE,
+    WINBOND_MANUFACTURER_ID,
+};
 /// Refer to datasheet:
 /// https://datasheet.lcsc.com/lcsc/1912111437_Winbond-Elec-W25Q128JVSIQ_C113767.pdf
 use crate::error::Error;
+use crate::identification::Identification;
+use crate::sfdp::{self, FlashGeometry};
 use embedded_hal_async::delay::DelayNs;
 use embedded_hal_async::spi::{Operation, SpiDevice};
 use hardware_traits::AsyncHardwareFlashDevice;
 
+/// tRES1: typical time needed after `release_power_down` before the device
+/// will accept another instruction, per the W25Q128JV datasheet.
+const DEFAULT_RELEASE_POWER_DOWN_DELAY_US: u32 = 3;
+
 pub struct AsyncFlashSpi<SPI, D> {
     pub spi: SPI,
     delay: D,
+    address_mode: AddressMode,
+    release_power_down_delay_us: u32,
+    capacity_bytes: Option<u32>,
 }
 
 impl<SPI, D> Debug for AsyncFlashSpi<SPI, D> {
@@ -41,20 +53,7 @@ where
     async fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI>> {
         // TODO what happens if `buf` is empty?
 
-        self.wait_done().await?;
-        let spi_result = self
-            .spi
-            .transaction(&mut [
-                Operation::Write(&[
-                    Opcode::Read as u8,
-                    (addr >> 16) as u8,
-                    (addr >> 8) as u8,
-                    addr as u8,
-                ]),
-                Operation::Read(buf),
-            ])
-            .await;
-        spi_result.map(|_| ()).map_err(Error::Spi)
+        self.read_with(ReadMode::Standard, addr, buf).await
     }
 
     /// Sector erase (see datasheet 8.2.15)
@@ -69,13 +68,9 @@ where
         self.wait_done().await?;
         self.write_enable().await?;
 
-        let cmd_buf = [
-            Opcode::SectorErase as u8,
-            (addr >> 16) as u8,
-            (addr >> 8) as u8,
-            addr as u8,
-        ];
-        self.command(&cmd_buf).await?;
+        let (cmd_buf, len) =
+            command_with_addr(self.address_mode, Opcode::SectorErase as u8, addr);
+        self.command(&cmd_buf[..len]).await?;
 
         Ok(())
     }
@@ -96,17 +91,10 @@ where
             defmt::warn!("WEL should be set: {:?}", self.read_status().await?);
         }
 
+        let (cmd_buf, len) = command_with_addr(self.address_mode, Opcode::PageProg as u8, addr);
         let spi_result = self
             .spi
-            .transaction(&mut [
-                Operation::Write(&[
-                    Opcode::PageProg as u8,
-                    (addr >> 16) as u8,
-                    (addr >> 8) as u8,
-                    addr as u8,
-                ]),
-                Operation::Write(data),
-            ])
+            .transaction(&mut [Operation::Write(&cmd_buf[..len]), Operation::Write(data)])
             .await;
         spi_result.map(|_| ()).map_err(Error::Spi)?;
         Ok(())
@@ -137,7 +125,24 @@ where
     D: DelayNs,
 {
     pub async fn init(spi: SPI, delay: D) -> Result<Self, Error<SPI>> {
-        let mut this = Self { spi, delay };
+        Self::init_with_manufacturer_check(spi, delay, None).await
+    }
+
+    /// Like `init`, but additionally checks that the device reports the
+    /// given JEDEC manufacturer ID (see `WINBOND_MANUFACTURER_ID`) before
+    /// returning, failing fast if a different part is attached.
+    pub async fn init_with_manufacturer_check(
+        spi: SPI,
+        delay: D,
+        expected_manufacturer: Option<u8>,
+    ) -> Result<Self, Error<SPI>> {
+        let mut this = Self {
+            spi,
+            delay,
+            address_mode: AddressMode::ThreeByte,
+            release_power_down_delay_us: DEFAULT_RELEASE_POWER_DOWN_DELAY_US,
+            capacity_bytes: None,
+        };
         let status = loop {
             let status = this.read_status().await?;
             if (status & (Status::BUSY)).is_empty() {
@@ -147,6 +152,14 @@ where
             this.delay.delay_ms(10).await;
         };
         defmt::debug!("Initial status: {:?}", status);
+
+        if let Some(expected) = expected_manufacturer {
+            let [manufacturer, _device] = this.read_manufacturer_device_id().await?;
+            if manufacturer != expected {
+                return Err(Error::UnexpectedManufacturer(manufacturer));
+            }
+        }
+
         Ok(this)
     }
 
@@ -190,6 +203,26 @@ where
 
         Ok(Status::from_bits_truncate(response[1]))
     }
+
+    pub async fn read_manufacturer_device_id(&mut self) -> Result<[u8; 2], Error<SPI>> {
+        let mut response = [0u8; 2];
+        self.command_with_response(&[Opcode::ReadMfDId as u8, 0, 0, 0], &mut response)
+            .await?;
+        Ok(response)
+    }
+
+    /// Reads the JEDEC manufacturer/device identification.
+    pub async fn read_jedec_id(&mut self) -> Result<Identification, Error<SPI>> {
+        // Optimistically read 12 bytes, even though some identifiers will be shorter
+        let mut buf: [u8; 12] = [0; 12];
+        buf[0] = Opcode::ReadJedecId as u8;
+        self.command_with_response(&[Opcode::ReadJedecId as u8], &mut buf)
+            .await?;
+
+        // Skip buf[0] (SPI read response byte)
+        Ok(Identification::from_jedec_id(&buf[1..]))
+    }
+
     /// Block until the status of the device is not busy
     async fn wait_done(&mut self) -> Result<(), Error<SPI>> {
         while self.read_status().await?.contains(Status::BUSY) {}
@@ -208,4 +241,210 @@ where
         self.command(&cmd_buf).await?;
         Ok(())
     }
+
+    /// Software reset (see datasheet 6.4)
+    /// The W25Q128JV can be reset to the initial power-on state by a software Reset
+    /// sequence. This sequence must include two consecutive instructions: Enable Reset
+    /// (66h) & Reset (99h). If the instruction sequence is successfully accepted, the
+    /// device will take approximately 30μS (tRST) to reset. No instruction will be
+    /// accepted during the reset period
+    pub async fn software_reset(&mut self) -> Result<(), Error<SPI>> {
+        self.wait_done().await?;
+        self.write_enable().await?;
+        let cmd_buf = [Opcode::EnableReset as u8];
+        self.command(&cmd_buf).await?;
+        self.wait_done().await?;
+        let cmd_buf = [Opcode::Reset as u8];
+        self.command(&cmd_buf).await?;
+        self.delay.delay_us(30).await;
+        Ok(())
+    }
+
+    /// Switches the device into 4-byte (32-bit) address mode (Enter4ByteAddr,
+    /// B7h), allowing `read`, `page_program` and `sector_erase` to address
+    /// chips larger than 16 MiB. Winbond parts require Write Enable to be set
+    /// before this instruction is accepted.
+    pub async fn enter_4byte_addr(&mut self) -> Result<(), Error<SPI>> {
+        self.wait_done().await?;
+        self.write_enable().await?;
+        let cmd_buf = [Opcode::Enter4ByteAddr as u8];
+        self.command(&cmd_buf).await?;
+        self.address_mode = AddressMode::FourByte;
+        Ok(())
+    }
+
+    /// Switches the device back to 3-byte (24-bit) address mode
+    /// (Exit4ByteAddr, E9h).
+    pub async fn exit_4byte_addr(&mut self) -> Result<(), Error<SPI>> {
+        self.wait_done().await?;
+        let cmd_buf = [Opcode::Exit4ByteAddr as u8];
+        self.command(&cmd_buf).await?;
+        self.address_mode = AddressMode::ThreeByte;
+        Ok(())
+    }
+
+    /// Returns the address mode currently in effect for this device.
+    pub fn address_mode(&self) -> AddressMode {
+        self.address_mode
+    }
+
+    /// Reads flash contents into `buf`, starting at `addr`, using the given
+    /// [`ReadMode`]. `ReadMode::Standard` matches the plain `read` instruction;
+    /// `ReadMode::Fast` trades a dummy byte for a higher clock frequency.
+    /// `ReadMode::Dual`/`ReadMode::Quad` are rejected with
+    /// `Error::UnsupportedReadMode`, since this driver has no multi-lane
+    /// transfer to drive them with; see the [`ReadMode`] docs.
+    pub async fn read_with(
+        &mut self,
+        mode: ReadMode,
+        addr: u32,
+        buf: &mut [u8],
+    ) -> Result<(), Error<SPI>> {
+        if mode.is_multi_lane() {
+            return Err(Error::UnsupportedReadMode);
+        }
+
+        self.wait_done().await?;
+        let (addr_cmd, addr_len) = command_with_addr(self.address_mode, mode.opcode(), addr);
+        let mut cmd_buf = [0u8; 6];
+        cmd_buf[..addr_len].copy_from_slice(&addr_cmd[..addr_len]);
+        let len = addr_len + mode.dummy_bytes();
+
+        let spi_result = self
+            .spi
+            .transaction(&mut [Operation::Write(&cmd_buf[..len]), Operation::Read(buf)])
+            .await;
+        spi_result.map(|_| ()).map_err(Error::Spi)
+    }
+
+    /// Reads `buf.len()` bytes of SFDP (Serial Flash Discoverable Parameters)
+    /// data starting at `addr`. See JESD216 for the table layout.
+    pub async fn read_sfdp(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI>> {
+        let cmd_buf = [
+            Opcode::ReadSfdp as u8,
+            (addr >> 16) as u8,
+            (addr >> 8) as u8,
+            addr as u8,
+            0, // dummy byte
+        ];
+        let spi_result = self
+            .spi
+            .transaction(&mut [Operation::Write(&cmd_buf), Operation::Read(buf)])
+            .await;
+        spi_result.map(|_| ()).map_err(Error::Spi)
+    }
+
+    /// Discovers the device's capacity and erase granularity by reading and
+    /// parsing its SFDP Basic Flash Parameter Table, rather than assuming a
+    /// particular W25 part.
+    pub async fn discover(&mut self) -> Result<FlashGeometry, Error<SPI>> {
+        let mut headers = [0u8; 136];
+        self.read_sfdp(0, &mut headers).await?;
+        let (ptr, len) = sfdp::find_basic_parameter_table(&headers).map_err(Error::Sfdp)?;
+
+        let mut table = [0u8; 64];
+        let len = len.min(table.len());
+        self.read_sfdp(ptr, &mut table[..len]).await?;
+        Ok(sfdp::parse_basic_parameter_table(&table))
+    }
+
+    /// Sets how long `release_power_down` waits (tRES1) before returning, to
+    /// accommodate W25 parts whose wake-up timing differs from the default.
+    pub fn set_release_power_down_delay_us(&mut self, delay_us: u32) {
+        self.release_power_down_delay_us = delay_us;
+    }
+
+    /// Deep Power-Down (see datasheet 8.2.19)
+    /// Puts the device into its lowest power consumption mode. The device
+    /// ignores all instructions except `release_power_down` until released.
+    pub async fn power_down(&mut self) -> Result<(), Error<SPI>> {
+        self.command(&[Opcode::PowerDown as u8]).await
+    }
+
+    /// Release from Power-Down (see datasheet 8.2.20)
+    /// Wakes the device from deep power-down, then waits tRES1 so the device
+    /// is ready to accept further instructions before returning.
+    pub async fn release_power_down(&mut self) -> Result<(), Error<SPI>> {
+        self.command(&[Opcode::ReleasePowerDown as u8]).await?;
+        self.delay.delay_us(self.release_power_down_delay_us).await;
+        Ok(())
+    }
+
+    /// Records the device's capacity, in bytes, so that `write` and
+    /// `erase_range` can reject out-of-bounds requests instead of silently
+    /// mirroring to a wrapped address. Typically populated from
+    /// `discover()`.
+    pub fn set_capacity_bytes(&mut self, capacity_bytes: u32) {
+        self.capacity_bytes = Some(capacity_bytes);
+    }
+
+    /// Writes `data` to `addr`, transparently splitting it at 256-byte page
+    /// boundaries and issuing one `page_program` per page. Unlike
+    /// `page_program`, `addr` need not be page-aligned.
+    pub async fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error<SPI>> {
+        self.check_bounds(addr, data.len() as u32)?;
+
+        let mut written = 0u32;
+        while (written as usize) < data.len() {
+            let page_addr = addr + written;
+            let page_offset = page_addr % PAGE_SIZE;
+            let chunk_len = (PAGE_SIZE - page_offset).min(data.len() as u32 - written);
+            self.page_program(
+                page_addr,
+                &data[written as usize..(written + chunk_len) as usize],
+            )
+            .await?;
+            written += chunk_len;
+        }
+        Ok(())
+    }
+
+    /// Erase a block of `size` bytes at `addr` (see datasheet 8.2.16/8.2.17).
+    /// Like `sector_erase`, `addr` must be aligned to `size` and a Write
+    /// Enable instruction is issued first.
+    pub async fn block_erase(&mut self, addr: u32, size: EraseSize) -> Result<(), Error<SPI>> {
+        self.wait_done().await?;
+        self.write_enable().await?;
+
+        let (cmd_buf, len) = command_with_addr(self.address_mode, size.opcode(), addr);
+        self.command(&cmd_buf[..len]).await?;
+        Ok(())
+    }
+
+    /// Erases `len` bytes starting at `addr`, issuing the largest aligned
+    /// block/sector erase that fits the remaining length at each step.
+    /// `addr` and `len` must already be 4K sector-aligned; they are not
+    /// rounded, and `Error::NotAligned` is returned otherwise.
+    pub async fn erase_range(&mut self, addr: u32, len: u32) -> Result<(), Error<SPI>> {
+        if addr % SECTOR_SIZE != 0 || len % SECTOR_SIZE != 0 {
+            return Err(Error::NotAligned);
+        }
+        self.check_bounds(addr, len)?;
+
+        let mut erased = 0u32;
+        while erased < len {
+            let cur_addr = addr + erased;
+            let remaining = len - erased;
+            let size = [EraseSize::Block64K, EraseSize::Block32K, EraseSize::Sector4K]
+                .into_iter()
+                .find(|size| cur_addr % size.bytes() == 0 && remaining >= size.bytes())
+                .unwrap_or(EraseSize::Sector4K);
+            self.block_erase(cur_addr, size).await?;
+            erased += size.bytes();
+        }
+        Ok(())
+    }
+
+    /// Returns `Error::OutOfBounds` if `[addr, addr + len)` extends past the
+    /// capacity set via `set_capacity_bytes`. A no-op if the capacity is
+    /// unknown.
+    fn check_bounds(&self, addr: u32, len: u32) -> Result<(), Error<SPI>> {
+        if let Some(capacity_bytes) = self.capacity_bytes {
+            let end = addr.checked_add(len).ok_or(Error::OutOfBounds)?;
+            if end > capacity_bytes {
+                return Err(Error::OutOfBounds);
+            }
+        }
+        Ok(())
+    }
 }
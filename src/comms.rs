@@ -2,6 +2,7 @@
 /// https://datasheet.lcsc.com/lcsc/1912111437_Winbond-Elec-W25Q128JVSIQ_C113767.pdf
 use crate::error::Error;
 use crate::identification::Identification;
+use crate::sfdp::{self, FlashGeometry};
 use core::fmt::Debug;
 use embedded_hal::spi::{Operation, SpiDevice};
 use hardware_traits::HardwareFlashDevice;
@@ -9,6 +10,8 @@ use hardware_traits::HardwareFlashDevice;
 // #[derive(Debug)]
 pub struct FlashSpi<SPI> {
     spi: SPI,
+    address_mode: AddressMode,
+    capacity_bytes: Option<u32>,
 }
 impl<SPI> Debug for FlashSpi<SPI> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -16,7 +19,7 @@ impl<SPI> Debug for FlashSpi<SPI> {
     }
 }
 
-enum Opcode {
+pub(crate) enum Opcode {
     /// Read the 8-bit manufacturer and device IDs.
     ReadMfDId = 0x90,
     /// Read 16-bit manufacturer ID and 8-bit device ID.
@@ -31,8 +34,151 @@ enum Opcode {
     ChipErase = 0xC7,
     EnableReset = 0x66,
     Reset = 0x99,
+    /// Switch the device to 4-byte (32-bit) addressing.
+    Enter4ByteAddr = 0xB7,
+    /// Switch the device back to 3-byte (24-bit) addressing.
+    Exit4ByteAddr = 0xE9,
+    /// Read the JEDEC Serial Flash Discoverable Parameters tables.
+    ReadSfdp = 0x5A,
+    /// Fast Read: like `Read`, but with a dummy byte after the address to
+    /// allow a higher clock frequency.
+    FastRead = 0x0B,
+    /// Fast Read Dual Output: data is returned two bits at a time.
+    DualOutputRead = 0x3B,
+    /// Fast Read Quad Output: data is returned four bits at a time.
+    QuadOutputRead = 0x6B,
+    /// Enter deep power-down mode.
+    PowerDown = 0xB9,
+    /// Release from deep power-down mode.
+    ReleasePowerDown = 0xAB,
+    /// Erase a 32 KiB block.
+    BlockErase32 = 0x52,
+    /// Erase a 64 KiB block.
+    BlockErase64 = 0xD8,
 }
 
+/// Selects the erase granularity for [`FlashSpi::block_erase`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EraseSize {
+    /// 4 KiB sector erase (Sector Erase, 20h).
+    Sector4K,
+    /// 32 KiB block erase (Block Erase, 52h).
+    Block32K,
+    /// 64 KiB block erase (Block Erase, D8h).
+    Block64K,
+}
+
+impl EraseSize {
+    pub(crate) fn opcode(self) -> u8 {
+        match self {
+            EraseSize::Sector4K => Opcode::SectorErase as u8,
+            EraseSize::Block32K => Opcode::BlockErase32 as u8,
+            EraseSize::Block64K => Opcode::BlockErase64 as u8,
+        }
+    }
+
+    /// The number of bytes erased by this granularity.
+    pub fn bytes(self) -> u32 {
+        match self {
+            EraseSize::Sector4K => SECTOR_SIZE,
+            EraseSize::Block32K => 32 * 1024,
+            EraseSize::Block64K => 64 * 1024,
+        }
+    }
+}
+
+/// Selects which read instruction [`FlashSpi::read_with`] issues. Faster
+/// modes require a dummy byte after the address before data is returned.
+///
+/// `Dual`/`Quad` name the opcodes that switch the device's data phase to two
+/// or four lines, but `embedded-hal`'s `SpiDevice`/`Operation` has no
+/// multi-lane transfer to drive them with, so `read_with` rejects them with
+/// `Error::UnsupportedReadMode` rather than issuing the command and reading
+/// back data over a single line that the device is no longer sending on.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReadMode {
+    /// Read Data (03h). No dummy byte, one data line.
+    #[default]
+    Standard,
+    /// Fast Read (0Bh). One dummy byte, one data line.
+    Fast,
+    /// Fast Read Dual Output (3Bh). One dummy byte, two data lines. Not
+    /// currently usable via `read_with`; see the enum docs.
+    Dual,
+    /// Fast Read Quad Output (6Bh). One dummy byte, four data lines. Not
+    /// currently usable via `read_with`; see the enum docs.
+    Quad,
+}
+
+impl ReadMode {
+    /// Whether this mode needs a multi-lane data phase that `read_with`
+    /// cannot currently drive.
+    pub(crate) fn is_multi_lane(self) -> bool {
+        matches!(self, ReadMode::Dual | ReadMode::Quad)
+    }
+
+    pub(crate) fn opcode(self) -> u8 {
+        match self {
+            ReadMode::Standard => Opcode::Read as u8,
+            ReadMode::Fast => Opcode::FastRead as u8,
+            ReadMode::Dual => Opcode::DualOutputRead as u8,
+            ReadMode::Quad => Opcode::QuadOutputRead as u8,
+        }
+    }
+
+    pub(crate) fn dummy_bytes(self) -> usize {
+        match self {
+            ReadMode::Standard => 0,
+            ReadMode::Fast | ReadMode::Dual | ReadMode::Quad => 1,
+        }
+    }
+}
+
+/// Selects how many address bytes are shifted out before a command's data
+/// phase. Winbond parts larger than 16 MiB (e.g. the W25Q256) need
+/// [`AddressMode::FourByte`] to address their full capacity; see
+/// [`Opcode::Enter4ByteAddr`] / [`Opcode::Exit4ByteAddr`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AddressMode {
+    /// 24-bit addresses (A23-A0). Supports up to 16 MiB.
+    #[default]
+    ThreeByte,
+    /// 32-bit addresses (A31-A0). Required above 16 MiB.
+    FourByte,
+}
+
+/// Serializes `opcode` followed by `addr` using either 3 or 4 address bytes,
+/// depending on `mode`. Returns the filled prefix of the buffer and the
+/// number of valid bytes in it.
+pub(crate) fn command_with_addr(mode: AddressMode, opcode: u8, addr: u32) -> ([u8; 5], usize) {
+    match mode {
+        AddressMode::ThreeByte => (
+            [opcode, (addr >> 16) as u8, (addr >> 8) as u8, addr as u8, 0],
+            4,
+        ),
+        AddressMode::FourByte => (
+            [
+                opcode,
+                (addr >> 24) as u8,
+                (addr >> 16) as u8,
+                (addr >> 8) as u8,
+                addr as u8,
+            ],
+            5,
+        ),
+    }
+}
+
+/// JEDEC manufacturer ID for Winbond Electronics, returned as the first byte
+/// of `read_jedec_id` / `read_manufacturer_device_id` on genuine W25-series
+/// parts.
+pub const WINBOND_MANUFACTURER_ID: u8 = 0xEF;
+
+/// Maximum number of bytes `page_program` can write in a single instruction.
+pub(crate) const PAGE_SIZE: u32 = 256;
+/// Number of bytes `sector_erase` erases in a single instruction.
+pub(crate) const SECTOR_SIZE: u32 = 4096;
+
 defmt::bitflags! {
     /// Status register bits.
     pub struct Status: u8 {
@@ -58,27 +204,19 @@ where
     /// Note that `addr` is not fully decoded: Flash chips will typically only
     /// look at the lowest `N` bits needed to encode their size, which means
     /// that the contents are "mirrored" to addresses that are a multiple of the
-    /// flash size. Only 24 bits of `addr` are transferred to the device in any
-    /// case, limiting the maximum size of 25-series SPI flash chips to 16 MiB.
+    /// flash size. By default only 24 bits of `addr` are transferred to the
+    /// device, limiting the maximum size of 25-series SPI flash chips to
+    /// 16 MiB; call [`FlashSpi::enter_4byte_addr`] first to address larger
+    /// parts.
     ///
     /// # Parameters
     ///
-    /// * `addr`: 24-bit address to start reading at.
+    /// * `addr`: Address to start reading at (24-bit, or 32-bit in 4-byte
+    ///   address mode).
     /// * `buf`: Destination buffer to fill.
     fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI>> {
         // TODO what happens if `buf` is empty?
-
-        self.wait_done()?;
-        let spi_result = self.spi.transaction(&mut [
-            Operation::Write(&[
-                Opcode::Read as u8,
-                (addr >> 16) as u8,
-                (addr >> 8) as u8,
-                addr as u8,
-            ]),
-            Operation::Read(buf),
-        ]);
-        spi_result.map(|_| ()).map_err(Error::Spi)
+        self.read_with(ReadMode::Standard, addr, buf)
     }
     /// Sector erase (see datasheet 8.2.15)
     /// The Sector Erase instruction sets all memory within a specified sector
@@ -92,13 +230,9 @@ where
         self.wait_done()?;
         self.write_enable()?;
 
-        let cmd_buf = [
-            Opcode::SectorErase as u8,
-            (addr >> 16) as u8,
-            (addr >> 8) as u8,
-            addr as u8,
-        ];
-        self.command(&cmd_buf)?;
+        let (cmd_buf, len) =
+            command_with_addr(self.address_mode, Opcode::SectorErase as u8, addr);
+        self.command(&cmd_buf[..len])?;
 
         Ok(())
     }
@@ -118,15 +252,10 @@ where
             defmt::warn!("WEL should be set: {:?}", self.read_status()?);
         }
 
-        let spi_result = self.spi.transaction(&mut [
-            Operation::Write(&[
-                Opcode::PageProg as u8,
-                (addr >> 16) as u8,
-                (addr >> 8) as u8,
-                addr as u8,
-            ]),
-            Operation::Write(data),
-        ]);
+        let (cmd_buf, len) = command_with_addr(self.address_mode, Opcode::PageProg as u8, addr);
+        let spi_result = self
+            .spi
+            .transaction(&mut [Operation::Write(&cmd_buf[..len]), Operation::Write(data)]);
         spi_result.map(|_| ()).map_err(Error::Spi)?;
         Ok(())
     }
@@ -165,6 +294,129 @@ where
         self.command(&cmd_buf)?;
         Ok(())
     }
+
+    /// Switches the device into 4-byte (32-bit) address mode (Enter4ByteAddr,
+    /// B7h), allowing `read`, `page_program` and `sector_erase` to address
+    /// chips larger than 16 MiB. Winbond parts require Write Enable to be set
+    /// before this instruction is accepted.
+    pub fn enter_4byte_addr(&mut self) -> Result<(), Error<SPI>> {
+        self.wait_done()?;
+        self.write_enable()?;
+        let cmd_buf = [Opcode::Enter4ByteAddr as u8];
+        self.command(&cmd_buf)?;
+        self.address_mode = AddressMode::FourByte;
+        Ok(())
+    }
+
+    /// Switches the device back to 3-byte (24-bit) address mode
+    /// (Exit4ByteAddr, E9h).
+    pub fn exit_4byte_addr(&mut self) -> Result<(), Error<SPI>> {
+        self.wait_done()?;
+        let cmd_buf = [Opcode::Exit4ByteAddr as u8];
+        self.command(&cmd_buf)?;
+        self.address_mode = AddressMode::ThreeByte;
+        Ok(())
+    }
+
+    /// Returns the address mode currently in effect for this device.
+    pub fn address_mode(&self) -> AddressMode {
+        self.address_mode
+    }
+
+    /// Deep Power-Down (see datasheet 8.2.19)
+    /// Puts the device into its lowest power consumption mode. The device
+    /// ignores all instructions except `release_power_down` until released.
+    pub fn power_down(&mut self) -> Result<(), Error<SPI>> {
+        let cmd_buf = [Opcode::PowerDown as u8];
+        self.command(&cmd_buf)
+    }
+
+    /// Release from Power-Down (see datasheet 8.2.20)
+    /// Wakes the device from deep power-down. The device needs tRES1 before
+    /// it will accept another instruction; this sync driver has no delay
+    /// source, so the caller is responsible for waiting that long before
+    /// issuing the next command.
+    pub fn release_power_down(&mut self) -> Result<(), Error<SPI>> {
+        let cmd_buf = [Opcode::ReleasePowerDown as u8];
+        self.command(&cmd_buf)
+    }
+
+    /// Records the device's capacity, in bytes, so that `write` and
+    /// `erase_range` can reject out-of-bounds requests instead of silently
+    /// mirroring to a wrapped address. Typically populated from
+    /// `discover()`.
+    pub fn set_capacity_bytes(&mut self, capacity_bytes: u32) {
+        self.capacity_bytes = Some(capacity_bytes);
+    }
+
+    /// Writes `data` to `addr`, transparently splitting it at 256-byte page
+    /// boundaries and issuing one `page_program` per page. Unlike
+    /// `page_program`, `addr` need not be page-aligned.
+    pub fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error<SPI>> {
+        self.check_bounds(addr, data.len() as u32)?;
+
+        let mut written = 0u32;
+        while (written as usize) < data.len() {
+            let page_addr = addr + written;
+            let page_offset = page_addr % PAGE_SIZE;
+            let chunk_len = (PAGE_SIZE - page_offset).min(data.len() as u32 - written);
+            self.page_program(
+                page_addr,
+                &data[written as usize..(written + chunk_len) as usize],
+            )?;
+            written += chunk_len;
+        }
+        Ok(())
+    }
+
+    /// Erase a block of `size` bytes at `addr` (see datasheet 8.2.16/8.2.17).
+    /// Like `sector_erase`, `addr` must be aligned to `size` and a Write
+    /// Enable instruction is issued first.
+    pub fn block_erase(&mut self, addr: u32, size: EraseSize) -> Result<(), Error<SPI>> {
+        self.wait_done()?;
+        self.write_enable()?;
+
+        let (cmd_buf, len) = command_with_addr(self.address_mode, size.opcode(), addr);
+        self.command(&cmd_buf[..len])?;
+        Ok(())
+    }
+
+    /// Erases `len` bytes starting at `addr`, issuing the largest aligned
+    /// block/sector erase that fits the remaining length at each step.
+    /// `addr` and `len` must already be 4K sector-aligned; they are not
+    /// rounded, and `Error::NotAligned` is returned otherwise.
+    pub fn erase_range(&mut self, addr: u32, len: u32) -> Result<(), Error<SPI>> {
+        if addr % SECTOR_SIZE != 0 || len % SECTOR_SIZE != 0 {
+            return Err(Error::NotAligned);
+        }
+        self.check_bounds(addr, len)?;
+
+        let mut erased = 0u32;
+        while erased < len {
+            let cur_addr = addr + erased;
+            let remaining = len - erased;
+            let size = [EraseSize::Block64K, EraseSize::Block32K, EraseSize::Sector4K]
+                .into_iter()
+                .find(|size| cur_addr % size.bytes() == 0 && remaining >= size.bytes())
+                .unwrap_or(EraseSize::Sector4K);
+            self.block_erase(cur_addr, size)?;
+            erased += size.bytes();
+        }
+        Ok(())
+    }
+
+    /// Returns `Error::OutOfBounds` if `[addr, addr + len)` extends past the
+    /// capacity set via `set_capacity_bytes`. A no-op if the capacity is
+    /// unknown.
+    fn check_bounds(&self, addr: u32, len: u32) -> Result<(), Error<SPI>> {
+        if let Some(capacity_bytes) = self.capacity_bytes {
+            let end = addr.checked_add(len).ok_or(Error::OutOfBounds)?;
+            if end > capacity_bytes {
+                return Err(Error::OutOfBounds);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<SPI> FlashSpi<SPI>
@@ -181,7 +433,11 @@ where
     }
 
     pub fn init(spi: SPI) -> Result<Self, Error<SPI>> {
-        let mut this = Self { spi };
+        let mut this = Self {
+            spi,
+            address_mode: AddressMode::ThreeByte,
+            capacity_bytes: None,
+        };
         let status = loop {
             let status = this.read_status()?;
             if (status & (Status::BUSY | Status::WEL)).is_empty() {
@@ -238,6 +494,64 @@ where
         Ok(Identification::from_jedec_id(&buf[1..]))
     }
 
+    /// Reads flash contents into `buf`, starting at `addr`, using the given
+    /// [`ReadMode`]. `ReadMode::Standard` matches the plain `read` instruction;
+    /// `ReadMode::Fast` trades a dummy byte for a higher clock frequency.
+    /// `ReadMode::Dual`/`ReadMode::Quad` are rejected with
+    /// `Error::UnsupportedReadMode`, since this driver has no multi-lane
+    /// transfer to drive them with; see the [`ReadMode`] docs.
+    pub fn read_with(
+        &mut self,
+        mode: ReadMode,
+        addr: u32,
+        buf: &mut [u8],
+    ) -> Result<(), Error<SPI>> {
+        if mode.is_multi_lane() {
+            return Err(Error::UnsupportedReadMode);
+        }
+
+        self.wait_done()?;
+        let (addr_cmd, addr_len) = command_with_addr(self.address_mode, mode.opcode(), addr);
+        let mut cmd_buf = [0u8; 6];
+        cmd_buf[..addr_len].copy_from_slice(&addr_cmd[..addr_len]);
+        let len = addr_len + mode.dummy_bytes();
+
+        let spi_result = self
+            .spi
+            .transaction(&mut [Operation::Write(&cmd_buf[..len]), Operation::Read(buf)]);
+        spi_result.map(|_| ()).map_err(Error::Spi)
+    }
+
+    /// Reads `buf.len()` bytes of SFDP (Serial Flash Discoverable Parameters)
+    /// data starting at `addr`. See JESD216 for the table layout.
+    pub fn read_sfdp(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI>> {
+        let cmd_buf = [
+            Opcode::ReadSfdp as u8,
+            (addr >> 16) as u8,
+            (addr >> 8) as u8,
+            addr as u8,
+            0, // dummy byte
+        ];
+        let spi_result = self
+            .spi
+            .transaction(&mut [Operation::Write(&cmd_buf), Operation::Read(buf)]);
+        spi_result.map(|_| ()).map_err(Error::Spi)
+    }
+
+    /// Discovers the device's capacity and erase granularity by reading and
+    /// parsing its SFDP Basic Flash Parameter Table, rather than assuming a
+    /// particular W25 part.
+    pub fn discover(&mut self) -> Result<FlashGeometry, Error<SPI>> {
+        let mut headers = [0u8; 136];
+        self.read_sfdp(0, &mut headers)?;
+        let (ptr, len) = sfdp::find_basic_parameter_table(&headers).map_err(Error::Sfdp)?;
+
+        let mut table = [0u8; 64];
+        let len = len.min(table.len());
+        self.read_sfdp(ptr, &mut table[..len])?;
+        Ok(sfdp::parse_basic_parameter_table(&table))
+    }
+
     /// Block until the status of the device is not busy
     fn wait_done(&mut self) -> Result<(), Error<SPI>> {
         while self.read_status()?.contains(Status::BUSY) {}
@@ -2,6 +2,8 @@ use core::fmt::{self, Debug};
 use defmt::{Format, Formatter};
 use embedded_hal::spi::SpiDevice;
 
+use crate::sfdp::SfdpError;
+
 /// The error type used by this library.
 ///
 /// This can encapsulate an SPI or GPIO error, and adds its own protocol errors
@@ -9,6 +11,20 @@ use embedded_hal::spi::SpiDevice;
 pub enum Error<SPI: SpiDevice> {
     /// An SPI transfer failed.
     Spi(SPI::Error),
+    /// Failed to parse the SFDP (Serial Flash Discoverable Parameters) tables.
+    Sfdp(SfdpError),
+    /// An address or length was not aligned to the block size the operation
+    /// requires.
+    NotAligned,
+    /// An operation's address range extends past the device's known
+    /// capacity.
+    OutOfBounds,
+    /// `init_with_manufacturer_check` found an unexpected JEDEC manufacturer
+    /// ID for the part attached.
+    UnexpectedManufacturer(u8),
+    /// The requested `ReadMode` needs a multi-lane data phase that this
+    /// driver cannot currently drive.
+    UnsupportedReadMode,
 }
 
 impl<SPI: SpiDevice> Format for Error<SPI>
@@ -18,6 +34,13 @@ where
     fn format(&self, fmt: Formatter) {
         match self {
             Error::Spi(_spi) => defmt::write!(fmt, "Error::Spi"),
+            Error::Sfdp(_err) => defmt::write!(fmt, "Error::Sfdp"),
+            Error::NotAligned => defmt::write!(fmt, "Error::NotAligned"),
+            Error::OutOfBounds => defmt::write!(fmt, "Error::OutOfBounds"),
+            Error::UnexpectedManufacturer(id) => {
+                defmt::write!(fmt, "Error::UnexpectedManufacturer({=u8})", id)
+            }
+            Error::UnsupportedReadMode => defmt::write!(fmt, "Error::UnsupportedReadMode"),
         }
     }
 }
@@ -28,6 +51,13 @@ where
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::Spi(spi) => write!(f, "Error::Spi({:?})", spi),
+            Error::Sfdp(err) => write!(f, "Error::Sfdp({:?})", err),
+            Error::NotAligned => write!(f, "Error::NotAligned"),
+            Error::OutOfBounds => write!(f, "Error::OutOfBounds"),
+            Error::UnexpectedManufacturer(id) => {
+                write!(f, "Error::UnexpectedManufacturer({:?})", id)
+            }
+            Error::UnsupportedReadMode => write!(f, "Error::UnsupportedReadMode"),
         }
     }
 }
@@ -0,0 +1,76 @@
+//! Parsing of the JEDEC Serial Flash Discoverable Parameters (SFDP) tables,
+//! as read via `Opcode::ReadSfdp` (see JESD216).
+
+/// Flash geometry discovered from the SFDP Basic Flash Parameter Table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashGeometry {
+    /// Total addressable capacity, in bytes.
+    pub capacity_bytes: u32,
+    /// Whether the device supports 4 KiB sector erase.
+    pub supports_4k_erase: bool,
+}
+
+/// Errors that can occur while parsing the SFDP tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SfdpError {
+    /// The "SFDP" signature was not found at address 0x000000.
+    BadSignature,
+    /// No Basic Flash Parameter Table (ID LSB 0x00, MSB 0xFF) was advertised
+    /// by any of the parameter headers.
+    MissingBasicParameterTable,
+}
+
+const SFDP_SIGNATURE: [u8; 4] = *b"SFDP";
+
+/// Finds the pointer and byte length of the Basic Flash Parameter Table
+/// within a raw dump of the SFDP header and parameter headers, starting at
+/// address 0x000000.
+pub(crate) fn find_basic_parameter_table(sfdp_header: &[u8]) -> Result<(u32, usize), SfdpError> {
+    if sfdp_header[0..4] != SFDP_SIGNATURE {
+        return Err(SfdpError::BadSignature);
+    }
+    // Byte 2 of the second dword: number of parameter headers, minus one.
+    // Clamp to what actually fits in the buffer: a device could in principle
+    // report more headers than we were given room to read.
+    let num_param_headers = (sfdp_header[6] as usize + 1).min((sfdp_header.len() - 8) / 8);
+    for i in 0..num_param_headers {
+        let header = &sfdp_header[8 + i * 8..16 + i * 8];
+        let id_lsb = header[0];
+        let id_msb = header[7];
+        if id_lsb == 0x00 && id_msb == 0xFF {
+            let len_bytes = header[3] as usize * 4;
+            let ptr = u32::from_le_bytes([header[4], header[5], header[6], 0]);
+            return Ok((ptr, len_bytes));
+        }
+    }
+    Err(SfdpError::MissingBasicParameterTable)
+}
+
+/// Parses the density (dword 2) and 4 KiB erase support (dword 1) fields of
+/// the Basic Flash Parameter Table into a [`FlashGeometry`].
+pub(crate) fn parse_basic_parameter_table(table: &[u8]) -> FlashGeometry {
+    let dword1 = u32::from_le_bytes(table[0..4].try_into().unwrap());
+    let dword2 = u32::from_le_bytes(table[4..8].try_into().unwrap());
+
+    // Bits 1:0 of DWORD 1: 01b indicates 4 KiB Erase is supported.
+    let supports_4k_erase = dword1 & 0b11 == 0b01;
+
+    // Bit 31 clear: density is the size in bits, minus 1. Bit 31 set: density
+    // is the size in bits, expressed as a power of two. The exponent is
+    // device-reported, so clamp it to avoid a shift-overflow panic (or a
+    // wrapped, bogus capacity in release builds) on a malformed table.
+    let capacity_bits: u64 = if dword2 & 0x8000_0000 == 0 {
+        dword2 as u64 + 1
+    } else {
+        let exponent = dword2 & 0x7FFF_FFFF;
+        if exponent >= 64 {
+            u64::MAX
+        } else {
+            1u64 << exponent
+        }
+    };
+    FlashGeometry {
+        capacity_bytes: (capacity_bits / 8).min(u32::MAX as u64) as u32,
+        supports_4k_erase,
+    }
+}